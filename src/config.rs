@@ -0,0 +1,104 @@
+/*!
+ * TOML configuration file support, so the controller can run unattended (e.g. from a systemd
+ * unit) without a long argument string.
+ */
+
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::Point;
+
+/** The persisted settings loaded from (or written to) a `--config` file */
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Config {
+    /** The fan curve points, deserialized from `[[point]]` tables */
+    #[serde(default, rename = "point")]
+    pub points: Vec<Point>,
+    /** How often, in seconds, to sample the temperature in `--daemon` mode */
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    /** The hysteresis margin applied on top of the curve, see [`crate::Hysteresis`] */
+    #[serde(default)]
+    pub hysteresis: f32,
+    /** `kp, ki, kd` gains, present when the PID controller is selected instead of the curve */
+    #[serde(default)]
+    pub pid: Option<(f32, f32, f32)>,
+    /** The PID setpoint, required alongside `pid` */
+    #[serde(default)]
+    pub target: Option<f32>,
+}
+
+fn default_interval() -> u64 {
+    2
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            points: vec![Point::new(0, 0.), Point::new(40, 50.), Point::new(60, 100.)],
+            interval: default_interval(),
+            hysteresis: 3.,
+            pid: None,
+            target: None,
+        }
+    }
+}
+
+impl Config {
+    /** Load the config at `path`, writing out the default config first if the file is missing */
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        if !path.exists() {
+            let config = Config::default();
+            fs::write(path, toml::to_string_pretty(&config)?)?;
+            return Ok(config);
+        }
+
+        Ok(toml::from_str(&fs::read_to_string(path)?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_writes_out_the_default_config_when_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+
+        let config = Config::load(&path).unwrap();
+
+        assert_eq!(config.points, Config::default().points);
+        assert_eq!(config.interval, default_interval());
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn load_reads_back_a_written_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        Config::load(&path).unwrap();
+
+        let config = Config::load(&path).unwrap();
+
+        assert_eq!(config.points, Config::default().points);
+    }
+
+    #[test]
+    fn load_prefers_an_existing_file_over_the_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(
+            &path,
+            "interval = 5\n\n[[point]]\ntemperature = 20\nspeed = 10.0\n",
+        )
+        .unwrap();
+
+        let config = Config::load(&path).unwrap();
+
+        assert_eq!(config.interval, 5);
+        assert_eq!(config.points, vec![Point::new(20, 10.)]);
+    }
+}
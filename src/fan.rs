@@ -0,0 +1,131 @@
+/*!
+ * [`Fan`] backends applying a computed PWM value: printing it to stdout for an external wrapper
+ * to pick up, or driving a GPIO pin directly via software PWM.
+ */
+
+use std::cell::RefCell;
+
+use rppal::gpio::{Gpio, OutputPin};
+
+/** A backend able to apply a PWM value, in the `0..=255` range */
+pub trait Fan {
+    fn apply(&self, pwm: u8) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+/** Prints `pwm_NNN` to stdout, for consumption by an external script or service */
+pub struct StdoutFan;
+
+impl Fan for StdoutFan {
+    fn apply(&self, pwm: u8) -> Result<(), Box<dyn std::error::Error>> {
+        println!("pwm_{:03}", pwm);
+
+        Ok(())
+    }
+}
+
+/** Drives a hardware pin directly with a software PWM signal, so the fan can be controlled
+ * without an external shell wrapper
+ */
+pub struct GpioFan {
+    /* `set_pwm_frequency` takes `&mut self`, but `Fan::apply` doesn't, hence the `RefCell` */
+    pin: RefCell<OutputPin>,
+    frequency: f64,
+}
+
+impl GpioFan {
+    pub fn new(pin: u8, frequency: f64) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(GpioFan {
+            pin: RefCell::new(Gpio::new()?.get(pin)?.into_output()),
+            frequency,
+        })
+    }
+}
+
+impl Fan for GpioFan {
+    fn apply(&self, pwm: u8) -> Result<(), Box<dyn std::error::Error>> {
+        let duty_cycle = pwm as f64 / 255.;
+
+        self.pin
+            .borrow_mut()
+            .set_pwm_frequency(self.frequency, duty_cycle)?;
+
+        Ok(())
+    }
+}
+
+/** The default software PWM frequency used for a `gpio:<pin>` backend without an explicit one */
+const DEFAULT_FREQUENCY: f64 = 25_000.;
+
+/** A `--fan` backend selection, resolved into a [`Fan`] at startup */
+pub enum Spec {
+    Stdout,
+    Gpio(u8, f64),
+}
+
+impl std::str::FromStr for Spec {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stdout" => Ok(Spec::Stdout),
+            _ => {
+                let rest = s.strip_prefix("gpio:").ok_or(())?;
+
+                Ok(match rest.splitn(2, ':').collect::<Vec<_>>()[..] {
+                    [pin, frequency] => Spec::Gpio(pin.parse().map_err(|_| ())?, frequency.parse().map_err(|_| ())?),
+                    [pin] => Spec::Gpio(pin.parse().map_err(|_| ())?, DEFAULT_FREQUENCY),
+                    _ => return Err(()),
+                })
+            }
+        }
+    }
+}
+
+impl Spec {
+    pub fn build(&self) -> Result<Box<dyn Fan>, Box<dyn std::error::Error>> {
+        Ok(match self {
+            Spec::Stdout => Box::new(StdoutFan),
+            Spec::Gpio(pin, frequency) => Box::new(GpioFan::new(*pin, *frequency)?),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn parses_stdout() {
+        assert!(matches!(Spec::from_str("stdout"), Ok(Spec::Stdout)));
+    }
+
+    #[test]
+    fn parses_gpio_with_default_frequency() {
+        let spec = Spec::from_str("gpio:18").unwrap();
+
+        assert!(matches!(spec, Spec::Gpio(18, frequency) if frequency == DEFAULT_FREQUENCY));
+    }
+
+    #[test]
+    fn parses_gpio_with_explicit_frequency() {
+        let spec = Spec::from_str("gpio:18:10000").unwrap();
+
+        assert!(matches!(spec, Spec::Gpio(18, frequency) if frequency == 10000.));
+    }
+
+    #[test]
+    fn rejects_input_missing_the_gpio_prefix() {
+        assert!(Spec::from_str("18").is_err());
+    }
+
+    #[test]
+    fn rejects_a_non_numeric_pin() {
+        assert!(Spec::from_str("gpio:nope").is_err());
+    }
+
+    #[test]
+    fn rejects_a_non_numeric_frequency() {
+        assert!(Spec::from_str("gpio:18:nope").is_err());
+    }
+}
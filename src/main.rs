@@ -2,20 +2,34 @@
  * This is to control the **DeskPi Pro Fan** using CPU's temperature
  */
 
-use std::collections::HashSet;
 use std::env;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
 
-use systemstat::{Platform, System};
+use serde::{Deserialize, Serialize};
 
-/** A point in the fan curve */
-#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+mod config;
+mod fan;
+mod pwm;
+mod sensor;
+
+use config::Config;
+use fan::Fan;
+use pwm::Range;
+use sensor::{Aggregation, HwmonSensor, Sensor, SystemStatSensor};
+
+/** A point in the fan curve, `speed` being a percentage in `0.0..=100.0` rather than a raw PWM
+ * code, so it can be scaled to whatever [`Range`] the hardware actually accepts
+ */
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Serialize, Deserialize)]
 struct Point {
     temperature: u8,
-    speed: u8,
+    speed: f32,
 }
 
 impl Point {
-    pub fn new(temperature: u8, speed: u8) -> Self {
+    pub fn new(temperature: u8, speed: f32) -> Self {
         Point { temperature, speed }
     }
 }
@@ -25,15 +39,18 @@ impl std::str::FromStr for Point {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         /* Split the string on `:` and match for two parts */
-        let (temperature, speed) = match s
-            .splitn(2, ':')
-            .map(|num| num.parse().map_err(|_| ()))
-            .collect::<Vec<Result<u8, ()>>>()[..]
-        {
-            [left, right] => (left?, right?),
+        let (temperature, speed) = match s.splitn(2, ':').collect::<Vec<_>>()[..] {
+            [temperature, speed] => (
+                temperature.parse::<u8>().map_err(|_| ())?,
+                speed.parse::<f32>().map_err(|_| ())?,
+            ),
             _ => return Err(()),
         };
 
+        if !(0.0..=100.0).contains(&speed) {
+            return Err(())
+        }
+
         Ok(Point { temperature, speed })
     }
 }
@@ -43,11 +60,29 @@ impl std::str::FromStr for Point {
 struct Curve(Vec<Point>);
 
 impl Curve {
-    pub fn from_points(points: Vec<Point>) -> Self {
-        Curve(points)
+    /** Build a curve from points already sorted by temperature, validating that the speed never
+     * decreases as the temperature rises, so [`Curve::bounds`] can never be handed a pair whose
+     * speed difference would be negative
+     */
+    pub fn from_points(points: Vec<Point>) -> Result<Self, Box<dyn std::error::Error>> {
+        if points
+            .iter()
+            .any(|point| !(0.0..=100.0).contains(&point.speed))
+        {
+            return Err("Fan curve speeds must be percentages in the 0.0..=100.0 range.".into())
+        }
+
+        if points.windows(2).any(|pair| pair[1].speed < pair[0].speed) {
+            return Err(
+                "Fan curve speeds must be non-decreasing as temperature rises.".into(),
+            )
+        }
+
+        Ok(Curve(points))
     }
 
-    fn calculate(&self, temperature: f32) -> u8 {
+    /** Compute the fan speed, as a `0.0..=100.0` percentage, for a given temperature */
+    fn calculate(&self, temperature: f32) -> f32 {
         let (lower, upper) = self.bounds(temperature);
 
         /* Calculate the difference in speed and temperature between the lower and upper bounds */
@@ -55,26 +90,31 @@ impl Curve {
             upper.temperature - lower.temperature,
             upper.speed - lower.speed,
         );
+        /* Both bounds are the same point, there is nothing to interpolate */
+        if tempdiff == 0 {
+            return lower.speed
+        }
+
         /* Calculate the percent of temperature overflow from the lower bound relative to the upper
          * bound */
         let percent = (temperature - lower.temperature as f32) / tempdiff as f32;
 
         /* Calculate speed from the proportionnal percentage of temperature */
-        (lower.speed as f32 + (speeddiff as f32 * percent)) as u8
+        lower.speed + speeddiff * percent
     }
 
     fn bounds(&self, temperature: f32) -> (Point, Point) {
-        let mut iter = self.0.clone().into_iter().peekable();
+        let mut iter = self.0.iter().copied().peekable();
 
         loop {
             /* Get lower and upper bounds for the current temperature */
             match (iter.next(), iter.peek()) {
-                (Some(point), None) => break (point.clone(), point),
-                (Some(point), Some(next))
+                (Some(point), None) => break (point, point),
+                (Some(point), Some(&next))
                     if temperature > (point.temperature as f32)
                         && temperature < (next.temperature as f32) =>
                 {
-                    break (point, next.clone())
+                    break (point, next)
                 }
                 (Some(_), Some(_)) => continue,
                 _ => panic!(),
@@ -83,34 +123,388 @@ impl Curve {
     }
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let sys = System::new();
+/** Smooths out the speed transitions computed from a [`Curve`] so that the fan doesn't stutter
+ * when the temperature hovers around a curve boundary.
+ *
+ * Speed is allowed to increase as soon as the curve says so, but a decrease is only let through
+ * once the temperature has dropped by `margin` below the point that triggered the current speed.
+ */
+#[derive(Debug)]
+struct Hysteresis {
+    margin: f32,
+    speed: f32,
+    trigger: f32,
+}
+
+impl Hysteresis {
+    pub fn new(margin: f32) -> Self {
+        Hysteresis {
+            margin,
+            speed: 0.,
+            trigger: 0.,
+        }
+    }
+
+    /** Feed a new temperature/speed sample (a `0.0..=100.0` percentage) and obtain the speed that
+     * should actually be applied
+     */
+    fn apply(&mut self, temperature: f32, speed: f32) -> f32 {
+        if speed >= self.speed {
+            /* Speed is rising (or steady), let it through immediately */
+            self.speed = speed;
+            self.trigger = temperature;
+        } else if temperature <= self.trigger - self.margin {
+            /* Temperature dropped far enough below the trigger point, let the speed decrease */
+            self.speed = speed;
+            self.trigger = temperature;
+        }
+
+        self.speed
+    }
+}
+
+/** A discrete velocity-form PID controller driving the fan speed towards a target temperature,
+ * as an alternative to interpolating a fixed [`Curve`].
+ *
+ * Since a *rising* temperature must *raise* the fan speed, the process variable (the temperature
+ * itself) is fed straight into the recurrence instead of the usual `target - input` error, which
+ * already gives the controller the right sign.
+ */
+#[derive(Debug)]
+struct Controller {
+    kp: f32,
+    ki: f32,
+    kd: f32,
+    target: f32,
+    output_min: f32,
+    output_max: f32,
+    x1: f32,
+    x2: f32,
+    u1: f32,
+    y1: f32,
+}
+
+impl Controller {
+    pub fn new(kp: f32, ki: f32, kd: f32, target: f32, range: Range) -> Self {
+        Controller {
+            kp,
+            ki,
+            kd,
+            target,
+            output_min: range.min as f32,
+            output_max: range.max as f32,
+            x1: target,
+            x2: target,
+            u1: target,
+            y1: 0.,
+        }
+    }
+
+    /** Feed a new temperature sample and obtain the clamped PWM output */
+    fn sample(&mut self, x0: f32) -> u8 {
+        let y0 = self.y1 - self.ki * self.target + x0 * (self.kp + self.ki + self.kd)
+            - self.x1 * (self.kp + 2. * self.kd)
+            + self.x2 * self.kd
+            + self.kp * (self.target - self.u1);
+        let y0 = y0.clamp(self.output_min, self.output_max);
+
+        self.x2 = self.x1;
+        self.x1 = x0;
+        self.u1 = self.target;
+        self.y1 = y0;
+
+        y0.round() as u8
+    }
+}
+
+/** The control strategy used to turn a sampled temperature into a PWM value */
+enum Mode {
+    Curve(Curve, Hysteresis, Range),
+    Pid(Controller),
+}
+
+impl Mode {
+    fn compute(&mut self, temperature: f32) -> u8 {
+        match self {
+            Mode::Curve(curve, hysteresis, range) => {
+                let percent = hysteresis.apply(temperature, curve.calculate(temperature));
+                range.scale(percent)
+            }
+            Mode::Pid(controller) => controller.sample(temperature),
+        }
+    }
+}
+
+/** Command line options controlling how `main` samples the temperature and applies the curve */
+struct Options {
+    points: Vec<Point>,
+    daemon: bool,
+    interval: u64,
+    hysteresis: f32,
+    pid: Option<(f32, f32, f32)>,
+    target: Option<f32>,
+    config: Option<PathBuf>,
+    sensor: Option<Aggregation>,
+    fan: Option<fan::Spec>,
+    pwm_path: Option<PathBuf>,
+}
+
+impl Options {
+    fn parse(args: impl Iterator<Item = String>) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut points = Vec::new();
+        let mut daemon = false;
+        let mut interval = 2;
+        let mut hysteresis = 0.;
+        let mut pid = None;
+        let mut target = None;
+        let mut config = None;
+        let mut sensor = None;
+        let mut fan = None;
+        let mut pwm_path = None;
+
+        let mut args = args.peekable();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--daemon" => daemon = true,
+                "--interval" => {
+                    interval = args
+                        .next()
+                        .ok_or("--interval requires a number of seconds")?
+                        .parse()?
+                }
+                "--hysteresis" => {
+                    hysteresis = args
+                        .next()
+                        .ok_or("--hysteresis requires a temperature margin")?
+                        .parse()?
+                }
+                "--pid" => {
+                    let raw = args.next().ok_or("--pid requires a kp,ki,kd triplet")?;
+                    pid = Some(match raw.splitn(3, ',').collect::<Vec<_>>()[..] {
+                        [kp, ki, kd] => (kp.parse()?, ki.parse()?, kd.parse()?),
+                        _ => return Err("--pid requires a kp,ki,kd triplet".into()),
+                    });
+                }
+                "--target" => {
+                    target = Some(
+                        args.next()
+                            .ok_or("--target requires a temperature")?
+                            .parse()?,
+                    )
+                }
+                "--config" => {
+                    config = Some(PathBuf::from(
+                        args.next().ok_or("--config requires a path")?,
+                    ))
+                }
+                "--sensor" => {
+                    sensor = Some(
+                        args.next()
+                            .ok_or("--sensor requires max, avg or a name:label pair")?
+                            .parse()
+                            .map_err(|_| {
+                                "--sensor requires max, avg or a name:label pair"
+                            })?,
+                    )
+                }
+                "--fan" => {
+                    fan = Some(
+                        args.next()
+                            .ok_or("--fan requires stdout or gpio:<pin>")?
+                            .parse()
+                            .map_err(|_| "--fan requires stdout or gpio:<pin>")?,
+                    )
+                }
+                "--pwm-path" => {
+                    pwm_path = Some(PathBuf::from(
+                        args.next().ok_or("--pwm-path requires a hwmon chip path")?,
+                    ))
+                }
+                _ => points.push(arg.parse().map_err(|_| {
+                    "Malformed input argument, the correct format is <temperature>:<speed>."
+                })?),
+            }
+        }
+
+        Ok(Options {
+            points,
+            daemon,
+            interval,
+            hysteresis,
+            pid,
+            target,
+            config,
+            sensor,
+            fan,
+            pwm_path,
+        })
+    }
+
+    /** Build the [`Sensor`] selected by these options, defaulting to `systemstat` */
+    fn sensor(&self) -> Box<dyn Sensor> {
+        match &self.sensor {
+            Some(aggregation) => Box::new(HwmonSensor(aggregation.clone())),
+            None => Box::new(SystemStatSensor::new()),
+        }
+    }
+
+    /** Build the [`Fan`] selected by these options, defaulting to stdout */
+    fn fan(&self) -> Result<Box<dyn Fan>, Box<dyn std::error::Error>> {
+        match &self.fan {
+            Some(spec) => spec.build(),
+            None => Ok(Box::new(fan::StdoutFan)),
+        }
+    }
 
-    /* Get points from the command line */
-    let points: HashSet<Point> = std::iter::once(Ok(Point::new(0, 0)))
-        .chain(env::args().skip(1).map(|s| s.parse()))
-        .collect::<Result<_, _>>()
-        .map_err(|_| "Malformed input argument, the correct format is <temperature>:<speed>.")?;
+    /** Build the [`Mode`] selected by these options, defaulting to the fan curve.
+     *
+     * A `--config` file, if given, takes precedence over the curve points and PID settings
+     * passed on the command line.
+     */
+    fn mode(self) -> Result<(Mode, u64), Box<dyn std::error::Error>> {
+        let range = match &self.pwm_path {
+            Some(path) => Range::read(path),
+            None => Range::default(),
+        };
 
-    /* Push them into a vector and sort it */
-    let mut points: Vec<_> = points.into_iter().collect();
-    points.sort();
+        let (points, interval, hysteresis, pid, target) = match self.config {
+            Some(path) => {
+                let config = Config::load(&path)?;
+                (
+                    config.points,
+                    config.interval,
+                    config.hysteresis,
+                    config.pid,
+                    config.target,
+                )
+            }
+            None => (
+                self.points,
+                self.interval,
+                self.hysteresis,
+                self.pid,
+                self.target,
+            ),
+        };
+
+        let mode = match (pid, target) {
+            (Some((kp, ki, kd)), Some(target)) => {
+                Mode::Pid(Controller::new(kp, ki, kd, target, range))
+            }
+            (Some(_), None) | (None, Some(_)) => {
+                return Err("--pid and --target must be provided together".into())
+            }
+            (None, None) => {
+                /* Get points from the command line or the loaded config, auto-inserting the zero
+                 * point like `main` always has */
+                let mut points: Vec<Point> = std::iter::once(Point::new(0, 0.))
+                    .chain(points)
+                    .collect();
+                /* `sort_by_key`/`partial_cmp` would panic on a NaN speed, so compare temperature
+                 * with a regular `Ord` and fall back to `total_cmp` for speed */
+                points.sort_by(|a, b| a.temperature.cmp(&b.temperature).then(a.speed.total_cmp(&b.speed)));
+                points.dedup();
 
-    if points.len() < 2 {
-        return Err("You must provide at least one more point in order to make a fan curve.".into())
+                if points.len() < 2 {
+                    return Err(
+                        "You must provide at least one more point in order to make a fan curve."
+                            .into(),
+                    )
+                }
+
+                Mode::Curve(Curve::from_points(points)?, Hysteresis::new(hysteresis), range)
+            }
+        };
+
+        Ok((mode, interval))
     }
+}
 
-    /* Obtain a curve from those points and the temperature from the sensors */
-    let curve = Curve::from_points(points);
-    let temperature = sys.cpu_temp()?;
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let options = Options::parse(env::args().skip(1))?;
+    let daemon = options.daemon;
+    let sensor = options.sensor();
+    let fan = options.fan()?;
+    let (mut mode, interval) = options.mode()?;
 
-    let pwm = curve.calculate(temperature);
+    if daemon {
+        loop {
+            apply(sensor.as_ref(), fan.as_ref(), &mut mode)?;
+            thread::sleep(Duration::from_secs(interval));
+        }
+    } else {
+        apply(sensor.as_ref(), fan.as_ref(), &mut mode)
+    }
+}
+
+/** Sample the current temperature, run it through the selected [`Mode`], and apply the PWM */
+fn apply(
+    sensor: &dyn Sensor,
+    fan: &dyn Fan,
+    mode: &mut Mode,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let temperature = sensor.read()?;
+    let pwm = mode.compute(temperature);
 
     eprintln!(
         ":i: Current temperature of `{}`, computed fan speed of `{}`",
         temperature, pwm
     );
-    println!("pwm_{:03}", pwm);
+    fan.apply(pwm)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn curve_interpolates_between_points() {
+        let curve = Curve::from_points(vec![
+            Point::new(0, 0.),
+            Point::new(40, 50.),
+            Point::new(80, 100.),
+        ])
+        .unwrap();
+
+        assert_eq!(curve.calculate(20.), 25.);
+    }
+
+    #[test]
+    fn curve_rejects_decreasing_speeds() {
+        let points = vec![Point::new(0, 50.), Point::new(40, 10.), Point::new(80, 100.)];
 
-    Ok(())
+        assert!(Curve::from_points(points).is_err());
+    }
+
+    #[test]
+    fn hysteresis_lets_rising_speed_through_immediately() {
+        let mut hysteresis = Hysteresis::new(3.);
+
+        assert_eq!(hysteresis.apply(50., 30.), 30.);
+    }
+
+    #[test]
+    fn hysteresis_holds_back_a_drop_within_the_margin() {
+        let mut hysteresis = Hysteresis::new(3.);
+        hysteresis.apply(50., 30.);
+
+        assert_eq!(hysteresis.apply(48., 20.), 30.);
+    }
+
+    #[test]
+    fn hysteresis_lets_speed_drop_past_the_margin() {
+        let mut hysteresis = Hysteresis::new(3.);
+        hysteresis.apply(50., 30.);
+
+        assert_eq!(hysteresis.apply(46., 20.), 20.);
+    }
+
+    #[test]
+    fn controller_steps_the_velocity_form_recurrence() {
+        let mut controller = Controller::new(1., 0., 0., 50., Range::default());
+
+        assert_eq!(controller.sample(60.), 10);
+        assert_eq!(controller.sample(70.), 20);
+    }
 }
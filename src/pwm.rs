@@ -0,0 +1,79 @@
+/*!
+ * The hardware PWM output range, used to scale a curve percentage into the code the fan actually
+ * expects, and to bound the PID controller's output.
+ */
+
+use std::fs;
+use std::path::Path;
+
+/** The `pwm1_min..=pwm1_max` range accepted by the fan, defaulting to the usual `0..=255` */
+#[derive(Debug, Clone, Copy)]
+pub struct Range {
+    pub min: u8,
+    pub max: u8,
+}
+
+impl Default for Range {
+    fn default() -> Self {
+        Range { min: 0, max: 255 }
+    }
+}
+
+impl Range {
+    /** Read `pwm1_min`/`pwm1_max` from a hwmon chip directory, falling back to the default for
+     * whichever of the two files is missing or, if the chip reports `min > max`, for the whole
+     * range
+     */
+    pub fn read(hwmon: &Path) -> Self {
+        let bound = |name: &str, default: u8| {
+            fs::read_to_string(hwmon.join(name))
+                .ok()
+                .and_then(|raw| raw.trim().parse().ok())
+                .unwrap_or(default)
+        };
+
+        let (min, max) = (
+            bound("pwm1_min", Range::default().min),
+            bound("pwm1_max", Range::default().max),
+        );
+
+        if min > max {
+            eprintln!(
+                ":! Ignoring invalid PWM range reported by `{}` (min {} > max {}), using the default 0..=255",
+                hwmon.display(),
+                min,
+                max
+            );
+            return Range::default()
+        }
+
+        Range { min, max }
+    }
+
+    /** Scale a `0.0..=100.0` percentage into this range, rounding rather than truncating */
+    pub fn scale(&self, percent: f32) -> u8 {
+        let span = self.max.saturating_sub(self.min) as f32;
+
+        (self.min as f32 + span * (percent.clamp(0., 100.) / 100.)).round() as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scale_maps_zero_and_full_speed_to_the_range_bounds() {
+        let range = Range::default();
+
+        assert_eq!(range.scale(0.), 0);
+        assert_eq!(range.scale(100.), 255);
+    }
+
+    #[test]
+    fn scale_rounds_rather_than_truncates() {
+        let range = Range::default();
+
+        assert_eq!(range.scale(50.), 128);
+    }
+}
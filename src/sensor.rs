@@ -0,0 +1,229 @@
+/*!
+ * [`Sensor`] backends feeding a temperature into the controller: the aggregate reading from
+ * `systemstat`, or one or more `hwmon` entries combined per an [`Aggregation`] policy, for boards
+ * where the SoC, NVMe and ambient sensors can differ significantly.
+ */
+
+use std::fs;
+use std::path::PathBuf;
+
+use systemstat::{Platform, System};
+
+/** A backend able to report the current temperature, in Celsius */
+pub trait Sensor {
+    fn read(&self) -> Result<f32, Box<dyn std::error::Error>>;
+}
+
+/** The aggregate CPU temperature as reported by `systemstat` */
+pub struct SystemStatSensor(System);
+
+impl SystemStatSensor {
+    pub fn new() -> Self {
+        SystemStatSensor(System::new())
+    }
+}
+
+impl Default for SystemStatSensor {
+    fn default() -> Self {
+        SystemStatSensor::new()
+    }
+}
+
+impl Sensor for SystemStatSensor {
+    fn read(&self) -> Result<f32, Box<dyn std::error::Error>> {
+        Ok(self.0.cpu_temp()?)
+    }
+}
+
+/** One or more `hwmon` sensors, combined per an [`Aggregation`] policy */
+pub struct HwmonSensor(pub Aggregation);
+
+impl Sensor for HwmonSensor {
+    fn read(&self) -> Result<f32, Box<dyn std::error::Error>> {
+        self.0.temperature()
+    }
+}
+
+/** A single `tempX_input` entry exposed by a `hwmon` chip */
+#[derive(Debug)]
+struct Entry {
+    chip: String,
+    label: Option<String>,
+    path: PathBuf,
+}
+
+impl Entry {
+    /** Read the entry, converting its millidegree reading to a plain Celsius [`f32`] */
+    fn read(&self) -> Result<f32, Box<dyn std::error::Error>> {
+        let millidegrees: i32 = fs::read_to_string(&self.path)?.trim().parse()?;
+
+        Ok(millidegrees as f32 / 1000.)
+    }
+}
+
+/** Enumerate every `tempX_input` entry under `/sys/class/hwmon/hwmonN/` */
+fn enumerate() -> Result<Vec<Entry>, Box<dyn std::error::Error>> {
+    let mut entries = Vec::new();
+
+    for chip in fs::read_dir("/sys/class/hwmon")? {
+        let chip = chip?.path();
+        let name = fs::read_to_string(chip.join("name"))?.trim().to_owned();
+
+        for entry in fs::read_dir(&chip)? {
+            let path = entry?.path();
+            let filename = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or_default()
+                .to_owned();
+
+            if let Some(prefix) = filename.strip_suffix("_input") {
+                if !prefix.starts_with("temp") {
+                    continue;
+                }
+
+                let label = fs::read_to_string(chip.join(format!("{}_label", prefix)))
+                    .ok()
+                    .map(|label| label.trim().to_owned());
+
+                entries.push(Entry {
+                    chip: name.clone(),
+                    label,
+                    path,
+                });
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+/** How to combine the readings of multiple `hwmon` entries into the single temperature fed to
+ * the controller
+ */
+#[derive(Debug, Clone)]
+pub enum Aggregation {
+    /** The highest reading among all sensors */
+    Max,
+    /** The average of all sensors' readings */
+    Avg,
+    /** The reading of the single sensor matching this `chip:label` pair */
+    Named(String, String),
+}
+
+impl std::str::FromStr for Aggregation {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "max" => Ok(Aggregation::Max),
+            "avg" => Ok(Aggregation::Avg),
+            _ => match s.splitn(2, ':').collect::<Vec<_>>()[..] {
+                [name, label] => Ok(Aggregation::Named(name.to_owned(), label.to_owned())),
+                _ => Err(()),
+            },
+        }
+    }
+}
+
+impl Aggregation {
+    /** Enumerate the `hwmon` entries and combine their readings according to this policy */
+    fn temperature(&self) -> Result<f32, Box<dyn std::error::Error>> {
+        let entries = enumerate()?;
+
+        let readings = entries
+            .iter()
+            .filter(|entry| self.matches(entry))
+            .map(Entry::read)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        self.combine(readings)
+    }
+
+    /** Whether `entry` is one this policy reads, pulled out of [`Aggregation::temperature`] so it
+     * can be tested without touching `/sys/class/hwmon`
+     */
+    fn matches(&self, entry: &Entry) -> bool {
+        match self {
+            Aggregation::Named(chip, label) => {
+                &entry.chip == chip && entry.label.as_deref() == Some(label.as_str())
+            }
+            Aggregation::Max | Aggregation::Avg => true,
+        }
+    }
+
+    /** Fold the readings already filtered by [`Aggregation::matches`] into the single temperature
+     * fed to the controller, pulled out of [`Aggregation::temperature`] so it can be tested
+     * without touching `/sys/class/hwmon`
+     */
+    fn combine(&self, readings: Vec<f32>) -> Result<f32, Box<dyn std::error::Error>> {
+        match self {
+            Aggregation::Max => readings
+                .into_iter()
+                .fold(None, |max, reading| Some(max.map_or(reading, |max: f32| max.max(reading))))
+                .ok_or_else(|| "No matching hwmon sensor found".into()),
+            Aggregation::Avg => {
+                if readings.is_empty() {
+                    return Err("No matching hwmon sensor found".into())
+                }
+
+                Ok(readings.iter().sum::<f32>() / readings.len() as f32)
+            }
+            Aggregation::Named(..) => readings
+                .into_iter()
+                .next()
+                .ok_or_else(|| "No matching hwmon sensor found".into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(chip: &str, label: Option<&str>) -> Entry {
+        Entry {
+            chip: chip.to_owned(),
+            label: label.map(str::to_owned),
+            path: PathBuf::new(),
+        }
+    }
+
+    #[test]
+    fn max_picks_the_highest_reading() {
+        assert_eq!(Aggregation::Max.combine(vec![40., 70., 55.]).unwrap(), 70.);
+    }
+
+    #[test]
+    fn avg_averages_the_readings() {
+        assert_eq!(Aggregation::Avg.combine(vec![40., 60.]).unwrap(), 50.);
+    }
+
+    #[test]
+    fn named_takes_the_first_matching_reading() {
+        assert_eq!(Aggregation::Named("k10temp".into(), "Tctl".into()).combine(vec![55.]).unwrap(), 55.);
+    }
+
+    #[test]
+    fn every_policy_errors_out_on_no_readings() {
+        assert!(Aggregation::Max.combine(vec![]).is_err());
+        assert!(Aggregation::Avg.combine(vec![]).is_err());
+        assert!(Aggregation::Named("k10temp".into(), "Tctl".into()).combine(vec![]).is_err());
+    }
+
+    #[test]
+    fn max_and_avg_match_every_entry() {
+        assert!(Aggregation::Max.matches(&entry("k10temp", Some("Tctl"))));
+        assert!(Aggregation::Avg.matches(&entry("nvme", None)));
+    }
+
+    #[test]
+    fn named_matches_only_the_chip_and_label_it_names() {
+        let policy = Aggregation::Named("k10temp".into(), "Tctl".into());
+
+        assert!(policy.matches(&entry("k10temp", Some("Tctl"))));
+        assert!(!policy.matches(&entry("k10temp", Some("Tccd1"))));
+        assert!(!policy.matches(&entry("nvme", Some("Tctl"))));
+        assert!(!policy.matches(&entry("k10temp", None)));
+    }
+}